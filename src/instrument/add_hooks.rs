@@ -0,0 +1,3 @@
+pub mod block_stack;
+pub mod cfg;
+pub mod loop_nest;
@@ -1,6 +1,6 @@
 use self::BlockStackElement::*;
-use std::collections::HashMap;
-use wasm::ast::{highlevel::Instr, Idx, Label};
+use std::collections::{HashMap, HashSet};
+use wasm::ast::{highlevel::{BlockType, Instr}, Idx, Label};
 
 /*
  * Data structure for representing the "control stack", i.e., the implicit nested block structure
@@ -23,31 +23,86 @@ pub struct BlockStack {
 pub enum BlockStackElement {
     Function {
         end: Idx<Instr>,
+        type_: BlockType,
+        /// operand-stack height when the function was entered, i.e., always 0
+        value_stack_height: usize,
+        /// whether the rest of this block is unreachable/polymorphic code (see `is_unreachable()`)
+        unreachable: bool,
     },
     Block {
         begin: Idx<Instr>,
         end: Idx<Instr>,
+        type_: BlockType,
+        /// operand-stack height at block entry, i.e., the height a branch to this block unwinds to
+        /// before the block's result values (if any) are kept on top, see `BranchTarget::target_value_stack_height()`
+        value_stack_height: usize,
+        unreachable: bool,
     },
     Loop {
         begin: Idx<Instr>,
         end: Idx<Instr>,
+        type_: BlockType,
+        value_stack_height: usize,
+        unreachable: bool,
     },
     If {
         #[serde(rename = "begin")]
         begin_if: Idx<Instr>,
         begin_else: Option<Idx<Instr>>,
         end: Idx<Instr>,
+        type_: BlockType,
+        value_stack_height: usize,
+        unreachable: bool,
     },
     Else {
         #[serde(rename = "begin")]
         begin_else: Idx<Instr>,
         begin_if: Idx<Instr>,
         end: Idx<Instr>,
+        type_: BlockType,
+        value_stack_height: usize,
+        unreachable: bool,
     },
 }
 
+impl BlockStackElement {
+    /// the block's result/parameter signature, as given on its Block/Loop/If instruction
+    /// (the function's signature, for the outermost Function element)
+    pub fn type_(&self) -> BlockType {
+        match *self {
+            Function { type_, .. } | Block { type_, .. } | Loop { type_, .. }
+            | If { type_, .. } | Else { type_, .. } => type_,
+        }
+    }
+
+    /// the operand-stack height at entry of this block, i.e., the height a branch to (or a return
+    /// across) this block unwinds to before the block's result values (if any) are kept on top
+    pub fn value_stack_height(&self) -> usize {
+        match *self {
+            Function { value_stack_height, .. } | Block { value_stack_height, .. } | Loop { value_stack_height, .. }
+            | If { value_stack_height, .. } | Else { value_stack_height, .. } => value_stack_height,
+        }
+    }
+
+    /// whether the remainder of this block is unreachable/polymorphic code, see `BlockStack::is_unreachable()`
+    pub fn unreachable(&self) -> bool {
+        match *self {
+            Function { unreachable, .. } | Block { unreachable, .. } | Loop { unreachable, .. }
+            | If { unreachable, .. } | Else { unreachable, .. } => unreachable,
+        }
+    }
+
+    /// the absolute instruction index of this block's matching End
+    pub fn end(&self) -> Idx<Instr> {
+        match *self {
+            Function { end, .. } | Block { end, .. } | Loop { end, .. }
+            | If { end, .. } | Else { end, .. } => end,
+        }
+    }
+}
+
 impl BlockStack {
-    pub fn new(instrs: &[Instr]) -> Self {
+    pub fn new(instrs: &[Instr], func_type: BlockType) -> Self {
         // build this already at construction, so that we know later in O(1) where the end's are
         let mut begin_end_map: HashMap<Idx<Instr>, Idx<Instr>> = HashMap::new();
 
@@ -70,28 +125,34 @@ impl BlockStack {
         assert!(begin_stack.is_empty(), "invalid block nesting: some blocks were not closed, stack at end is {:?}", begin_stack);
 
         BlockStack {
-            block_stack: vec![Function { end: (instrs.len() - 1).into() }],
+            block_stack: vec![Function { end: (instrs.len() - 1).into(), type_: func_type, value_stack_height: 0, unreachable: false }],
             begin_end_map,
         }
     }
 
-    pub fn begin_block(&mut self, begin: Idx<Instr>) {
+    pub fn begin_block(&mut self, begin: Idx<Instr>, type_: BlockType, value_stack_height: usize) {
         self.block_stack.push(Block {
             begin,
             end: *self.begin_end_map.get(&begin)
                 .expect(&format!("invalid block nesting: could not find end for block begin at {:?}", begin)),
+            type_,
+            value_stack_height,
+            unreachable: false,
         });
     }
 
-    pub fn begin_loop(&mut self, begin: Idx<Instr>) {
+    pub fn begin_loop(&mut self, begin: Idx<Instr>, type_: BlockType, value_stack_height: usize) {
         self.block_stack.push(Loop {
             begin,
             end: *self.begin_end_map.get(&begin)
                 .expect(&format!("invalid block nesting: could not find end for loop begin at {:?}", begin)),
+            type_,
+            value_stack_height,
+            unreachable: false,
         });
     }
 
-    pub fn begin_if(&mut self, begin_if: Idx<Instr>) {
+    pub fn begin_if(&mut self, begin_if: Idx<Instr>, type_: BlockType, value_stack_height: usize) {
         let end_or_else = *self.begin_end_map.get(&begin_if)
             .expect(&format!("invalid block nesting: could not find end/else for if begin at {:?}", begin_if));
 
@@ -100,12 +161,18 @@ impl BlockStack {
                 begin_if,
                 begin_else: Some(end_or_else),
                 end,
+                type_,
+                value_stack_height,
+                unreachable: false,
             }
         } else {
             If {
                 begin_if,
                 begin_else: None,
                 end: end_or_else,
+                type_,
+                value_stack_height,
+                unreachable: false,
             }
         };
         self.block_stack.push(if_);
@@ -115,8 +182,9 @@ impl BlockStack {
     pub fn else_(&mut self) -> BlockStackElement {
         match self.block_stack.pop() {
             Some(block_element) => match block_element {
-                If { begin_if, begin_else: Some(begin_else), end } => {
-                    self.block_stack.push(Else { begin_if, begin_else, end });
+                If { begin_if, begin_else: Some(begin_else), end, type_, value_stack_height, .. } => {
+                    // the else branch starts out reachable, regardless of whether the if branch was
+                    self.block_stack.push(Else { begin_if, begin_else, end, type_, value_stack_height, unreachable: false });
                     block_element
                 }
                 block => panic!("invalid block nesting: expected if with else on block stack, but got {:?}", block),
@@ -129,6 +197,34 @@ impl BlockStack {
         self.block_stack.pop().expect("invalid block nesting: could not end block, stack was empty")
     }
 
+    /// resolves the implicit "taken" target of the if currently on top of the block stack, i.e.,
+    /// where control goes when the if's condition is false at runtime: the else branch if
+    /// present, otherwise just past the end (mirrors the +1 convention of br_target/return_target)
+    pub fn if_false_target(&self) -> Idx<Instr> {
+        match self.block_stack.last() {
+            Some(If { begin_else: Some(begin_else), .. }) => *begin_else,
+            Some(If { begin_else: None, end, .. }) => *end,
+            top => panic!("invalid block nesting: expected if on top of block stack, got {:?}", top),
+        }
+    }
+
+    /// whether the current instruction is in unreachable/polymorphic code, i.e., the remainder
+    /// of the innermost block is statically dead because of a preceding unconditional
+    /// control-transfer instruction (`unreachable`, `br`, `br_table`, `return`)
+    pub fn is_unreachable(&self) -> bool {
+        self.block_stack.last().expect("block stack is never empty, it always contains at least the function block").unreachable()
+    }
+
+    /// marks the remainder of the current block as unreachable/polymorphic; call on encountering
+    /// `unreachable`, `br`, `br_table`, or `return`. Cleared again by `else_()`/`end()`, since
+    /// those move to a new block (the else branch, or the block enclosing the one just ended)
+    pub fn set_unreachable(&mut self) {
+        match self.block_stack.last_mut().expect("block stack is never empty, it always contains at least the function block") {
+            Function { unreachable, .. } | Block { unreachable, .. } | Loop { unreachable, .. }
+            | If { unreachable, .. } | Else { unreachable, .. } => *unreachable = true,
+        }
+    }
+
     /// resolves a relative label at the current instruction to an absolute instruction index
     /// this requires forward scanning for non-loop block ends (implemented as a precomputed HashMap lookup, so O(1))
     pub fn br_target(&self, label: Idx<Label>) -> BranchTarget {
@@ -144,17 +240,30 @@ impl BlockStack {
 
             match *target_block {
                 Loop { begin, .. } => begin,
-                Function { end } | Block { end, .. } | If { end, .. } | Else { end, .. } => end,
+                Function { end, .. } | Block { end, .. } | If { end, .. } | Else { end, .. } => end,
             }
         };
 
         BranchTarget { absolute_instr, ended_blocks }
     }
 
+    /// resolves all case labels of a br_table (plus the default, appended last) to their branch
+    /// targets in one call, so that instrumentation hooks for br_table don't have to call
+    /// br_target() in a loop themselves to build a complete switch-edge map; targets are
+    /// deduplicated by their resolved instruction, since multiple cases (or a case and the
+    /// default) commonly jump to the same place
+    pub fn br_table_targets(&self, labels: &[Idx<Label>], default: Idx<Label>) -> Vec<BranchTarget> {
+        let mut seen: HashSet<Idx<Instr>> = HashSet::new();
+        labels.iter().map(|&label| self.br_target(label))
+            .chain(std::iter::once(self.br_target(default)))
+            .filter(|target| seen.insert(target.absolute_instr))
+            .collect()
+    }
+
     /// similar to br_target(), call to get all implicitly ended blocks by a return
     pub fn return_target(&self) -> BranchTarget {
         BranchTarget {
-            absolute_instr: if let Some(Function { end }) = self.block_stack.first() {
+            absolute_instr: if let Some(Function { end, .. }) = self.block_stack.first() {
                 *end
             } else {
                 panic!("missing function block on block stack")
@@ -173,3 +282,51 @@ pub struct BranchTarget {
     /// in the order how they are left (i.e., innermost [== current block] to outermost [== target block])
     pub ended_blocks: Vec<BlockStackElement>,
 }
+
+impl BranchTarget {
+    /// the target block of this branch, i.e., the last (outermost) of the ended blocks
+    pub fn target_block(&self) -> &BlockStackElement {
+        self.ended_blocks.last().expect("ended_blocks is never empty, it always contains at least the target block")
+    }
+
+    /// the operand-stack height a branch hook should restore the shadow stack to after taking
+    /// this branch, i.e., the target block's entry height plus the values it produces.
+    /// a branch to a Loop re-enters its body instead of falling out of it, so it never produces
+    /// a value there (the loop's `type_` is its *result* type, not relevant on this backward edge)
+    pub fn target_value_stack_height(&self) -> usize {
+        let target = self.target_block();
+        match *target {
+            Loop { .. } => target.value_stack_height(),
+            _ => target.value_stack_height() + match target.type_() {
+                BlockType(Some(_)) => 1,
+                BlockType(None) => 0,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wasm::ast::ValType;
+
+    #[test]
+    fn target_value_stack_height_of_loop_branch_ignores_result_type() {
+        // (loop (result i32) (br 0)) end ;; the loop's own End, then the function's End
+        let instrs = vec![
+            Instr::Loop(BlockType(Some(ValType::I32))),
+            Instr::Br(0.into()),
+            Instr::End,
+            Instr::End,
+        ];
+        let mut block_stack = BlockStack::new(&instrs, BlockType(None));
+        block_stack.begin_loop(0.into(), BlockType(Some(ValType::I32)), 3);
+
+        let target = block_stack.br_target(0.into());
+
+        // branching back to the loop header must restore the shadow stack to the height at
+        // loop entry, not +1 for the loop's result type: that result is only produced when
+        // falling out of the loop normally, never when branching back into it
+        assert_eq!(target.target_value_stack_height(), 3);
+    }
+}
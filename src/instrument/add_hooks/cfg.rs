@@ -0,0 +1,144 @@
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
+use wasm::ast::{highlevel::{BlockType, Instr}, Idx};
+
+use super::block_stack::BlockStack;
+
+/*
+ * Basic-block CFG on top of BlockStack: partitions a function into maximal straight-line
+ * basic blocks and the successor edges between them.
+ * Needed for per-basic-block instrumentation (block coverage, edge counters) instead of
+ * only per-instruction hooks.
+ */
+
+/// identifies a basic block by the instruction index of its leader (first instruction)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct BlockId(pub Idx<Instr>);
+
+#[derive(Debug, Clone)]
+pub struct BasicBlock {
+    pub id: BlockId,
+    /// half-open range of instructions belonging to this block
+    pub instrs: Range<Idx<Instr>>,
+}
+
+#[derive(Debug)]
+pub struct Cfg {
+    pub blocks: Vec<BasicBlock>,
+    pub edges: HashMap<BlockId, Vec<BlockId>>,
+}
+
+impl Cfg {
+    /// builds the basic-block CFG of a function from its instruction stream
+    pub fn new(instrs: &[Instr], func_type: BlockType) -> Self {
+        let mut block_stack = BlockStack::new(instrs, func_type);
+
+        // pass 1: find all leaders (first instructions of a basic block) and, for every
+        // instruction that ends a block (branch or unreachable), its resolved successors
+        let mut leaders: HashSet<usize> = HashSet::new();
+        leaders.insert(0);
+        let mut successors: HashMap<usize, Vec<usize>> = HashMap::new();
+
+        // mirror BlockStack::new(): the trailing End has no matching begin (it's the implicit
+        // function frame), so don't drive the stack over it, or end() would pop past Function
+        for (iidx, instr) in instrs[..instrs.len() - 1].iter().enumerate() {
+            let idx: Idx<Instr> = iidx.into();
+
+            // drive the block stack the same way the instrumentation driver does, so that
+            // br_target()/if_false_target() below see the nesting at exactly this instruction
+            // the CFG only cares about control flow, not the operand stack, so pass 0 for the
+            // block entry height everywhere: it is stored on the frame but never read here
+            let mut else_join: Option<usize> = None;
+            match *instr {
+                Instr::Block(ty) => block_stack.begin_block(idx, ty, 0),
+                Instr::Loop(ty) => block_stack.begin_loop(idx, ty, 0),
+                Instr::If(ty) => block_stack.begin_if(idx, ty, 0),
+                Instr::Else => {
+                    // an Else that falls through (rather than branching) merges with the rest
+                    // of the function just past the whole if/else, not into the else-body
+                    else_join = Some(block_stack.else_().end().0 + 1);
+                }
+                Instr::End => { block_stack.end(); }
+                _ => {}
+            }
+
+            let next = iidx + 1;
+            match *instr {
+                Instr::Else => {
+                    let join = else_join.expect("else_join is always set when instr is Else");
+                    leaders.insert(join);
+                    successors.insert(iidx, vec![join]);
+                }
+                Instr::If(_) => {
+                    // a conditional fork, just like BrIf: fall through into the then-body, or
+                    // jump to the else body (or past the end, if there is no else) when false
+                    let taken = block_stack.if_false_target().0 + 1;
+                    leaders.insert(taken);
+                    if next < instrs.len() {
+                        leaders.insert(next);
+                    }
+                    successors.insert(iidx, vec![next, taken]);
+                }
+                Instr::Br(label) => {
+                    let leader = block_stack.br_target(label).absolute_instr.0 + 1;
+                    leaders.insert(leader);
+                    successors.insert(iidx, vec![leader]);
+                }
+                Instr::BrIf(label) => {
+                    let leader = block_stack.br_target(label).absolute_instr.0 + 1;
+                    leaders.insert(leader);
+                    if next < instrs.len() {
+                        leaders.insert(next);
+                    }
+                    successors.insert(iidx, vec![next, leader]);
+                }
+                Instr::BrTable { ref table, default } => {
+                    let targets: Vec<usize> = block_stack.br_table_targets(table, default)
+                        .iter()
+                        .map(|target| target.absolute_instr.0 + 1)
+                        .collect();
+                    for &leader in &targets {
+                        leaders.insert(leader);
+                    }
+                    successors.insert(iidx, targets);
+                }
+                Instr::Return | Instr::Unreachable => {
+                    successors.insert(iidx, vec![]);
+                }
+                _ => continue,
+            }
+            if next < instrs.len() {
+                leaders.insert(next);
+            }
+        }
+
+        // pass 2: partition the instructions into basic blocks between consecutive leaders
+        let mut sorted_leaders: Vec<usize> = leaders.into_iter().collect();
+        sorted_leaders.sort_unstable();
+
+        let blocks: Vec<BasicBlock> = sorted_leaders.iter().enumerate().map(|(i, &leader)| {
+            let block_end = sorted_leaders.get(i + 1).cloned().unwrap_or_else(|| instrs.len());
+            BasicBlock {
+                id: BlockId(leader.into()),
+                instrs: Idx::from(leader)..Idx::from(block_end),
+            }
+        }).collect();
+
+        // pass 3: connect the blocks, defaulting to fall-through into the next block unless
+        // the last instruction of a block was an explicit branch (or had no successors)
+        let mut edges: HashMap<BlockId, Vec<BlockId>> = HashMap::new();
+        for (i, block) in blocks.iter().enumerate() {
+            let last_instr = block.instrs.end.0 - 1;
+            let targets = match successors.get(&last_instr) {
+                Some(targets) => targets.iter().cloned().map(|target| BlockId(target.into())).collect(),
+                None => match blocks.get(i + 1) {
+                    Some(next_block) => vec![next_block.id],
+                    None => vec![],
+                },
+            };
+            edges.insert(block.id, targets);
+        }
+
+        Cfg { blocks, edges }
+    }
+}
@@ -0,0 +1,166 @@
+use std::collections::{HashMap, HashSet};
+
+use super::cfg::{BlockId, Cfg};
+
+/*
+ * Natural loop and loop-nest detection, built on top of Cfg.
+ * Finds back edges via a reverse-postorder numbering, derives each one's natural loop body,
+ * and nests the resulting loops by body containment.
+ * Lets instrumentation report loop-back-edge counts and per-loop iteration profiles.
+ */
+
+#[derive(Debug)]
+pub enum Node {
+    Loop(BlockId, Vec<Node>),
+    Leaf(BlockId),
+}
+
+#[derive(Debug)]
+pub struct LoopNest {
+    pub forest: Vec<Node>,
+    pub depth: HashMap<BlockId, u32>,
+}
+
+/// a natural loop: its header and the full set of blocks in its body (including the header)
+struct Loop {
+    header: BlockId,
+    body: HashSet<BlockId>,
+}
+
+impl LoopNest {
+    pub fn compute(cfg: &Cfg) -> Self {
+        let rpo = reverse_postorder(cfg);
+
+        // find back edges, then the natural loop for each, merging bodies that share a header
+        let mut loops_by_header: HashMap<BlockId, Loop> = HashMap::new();
+        for block in &cfg.blocks {
+            for &succ in cfg.edges.get(&block.id).into_iter().flatten() {
+                let is_back_edge = match (rpo.get(&block.id), rpo.get(&succ)) {
+                    (Some(&b_rpo), Some(&h_rpo)) => h_rpo <= b_rpo,
+                    _ => false,
+                };
+                if !is_back_edge {
+                    continue;
+                }
+                let body = natural_loop_body(cfg, block.id, succ);
+                loops_by_header.entry(succ)
+                    .and_modify(|loop_| loop_.body.extend(&body))
+                    .or_insert(Loop { header: succ, body });
+            }
+        }
+
+        // nesting: a loop A is a child of loop B iff A's body is a strict subset of B's body;
+        // reducible (structured WASM) control flow guarantees loop bodies are properly nested,
+        // i.e. never partially overlapping, which we assert here
+        let mut loops: Vec<&Loop> = loops_by_header.values().collect();
+        for (i, a) in loops.iter().enumerate() {
+            for b in loops.iter().skip(i + 1) {
+                let overlaps = a.body.intersection(&b.body).next().is_some();
+                let nested = a.body.is_subset(&b.body) || b.body.is_subset(&a.body);
+                assert!(!overlaps || nested, "irreducible control flow: loop bodies {:?} and {:?} partially overlap", a.header, b.header);
+            }
+        }
+        // largest bodies first, so parents are placed before their children
+        loops.sort_by_key(|loop_| std::cmp::Reverse(loop_.body.len()));
+
+        let mut depth: HashMap<BlockId, u32> = HashMap::new();
+        for block in &cfg.blocks {
+            depth.insert(block.id, 0);
+        }
+        let forest = build_forest(&loops, &cfg.blocks.iter().map(|block| block.id).collect::<Vec<_>>(), &mut depth, 1);
+
+        LoopNest { forest, depth }
+    }
+}
+
+/// builds the nesting forest for the blocks not yet claimed by an already-placed parent loop
+fn build_forest(loops: &[&Loop], blocks: &[BlockId], depth: &mut HashMap<BlockId, u32>, current_depth: u32) -> Vec<Node> {
+    let mut handled: HashSet<BlockId> = HashSet::new();
+    let mut nodes = vec![];
+
+    for loop_ in loops {
+        if handled.contains(&loop_.header) || !blocks.contains(&loop_.header) {
+            continue;
+        }
+        // this loop is a top-level loop among `blocks` only if none of the other, still
+        // unhandled loops among `blocks` properly contains it
+        let is_top_level = loops.iter().all(|other| {
+            other.header == loop_.header || !(loop_.body.is_subset(&other.body) && other.body.contains(&loop_.header) && blocks.contains(&other.header))
+        });
+        if !is_top_level {
+            continue;
+        }
+
+        let body: Vec<BlockId> = loop_.body.iter().cloned().collect();
+        for &block in &body {
+            depth.insert(block, current_depth.max(*depth.get(&block).unwrap_or(&0)));
+            handled.insert(block);
+        }
+
+        let inner_loops: Vec<&Loop> = loops.iter().cloned().filter(|other| other.header != loop_.header && loop_.body.contains(&other.header)).collect();
+        let children = build_forest(&inner_loops, &body, depth, current_depth + 1);
+        nodes.push(Node::Loop(loop_.header, children));
+    }
+
+    for &block in blocks {
+        if !handled.contains(&block) {
+            nodes.push(Node::Leaf(block));
+        }
+    }
+    nodes
+}
+
+/// reverse postorder numbering of all reachable blocks, via a DFS from the entry block
+fn reverse_postorder(cfg: &Cfg) -> HashMap<BlockId, usize> {
+    let entry = match cfg.blocks.first() {
+        Some(block) => block.id,
+        None => return HashMap::new(),
+    };
+
+    let mut postorder = vec![];
+    let mut visited: HashSet<BlockId> = HashSet::new();
+    let mut stack: Vec<(BlockId, usize)> = vec![(entry, 0)];
+    visited.insert(entry);
+
+    while let Some(&mut (block, ref mut next_succ)) = stack.last_mut() {
+        let succs = cfg.edges.get(&block).map(Vec::as_slice).unwrap_or(&[]);
+        if *next_succ < succs.len() {
+            let succ = succs[*next_succ];
+            *next_succ += 1;
+            if visited.insert(succ) {
+                stack.push((succ, 0));
+            }
+        } else {
+            postorder.push(block);
+            stack.pop();
+        }
+    }
+
+    postorder.into_iter().rev().enumerate().map(|(rpo, block)| (block, rpo)).collect()
+}
+
+/// blocks that can reach `tail` without passing through `header`, plus `header` itself
+fn natural_loop_body(cfg: &Cfg, tail: BlockId, header: BlockId) -> HashSet<BlockId> {
+    let mut predecessors: HashMap<BlockId, Vec<BlockId>> = HashMap::new();
+    for block in &cfg.blocks {
+        for &succ in cfg.edges.get(&block.id).into_iter().flatten() {
+            predecessors.entry(succ).or_insert_with(Vec::new).push(block.id);
+        }
+    }
+
+    let mut body: HashSet<BlockId> = HashSet::new();
+    body.insert(header);
+    body.insert(tail);
+    let mut worklist = vec![tail];
+    while let Some(block) = worklist.pop() {
+        if block == header {
+            continue;
+        }
+        for &pred in predecessors.get(&block).into_iter().flatten() {
+            if body.insert(pred) {
+                worklist.push(pred);
+            }
+        }
+    }
+    body
+}